@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::{Client, RequestBuilder};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time;
+
+/// Points older than this are considered stale and are dropped instead of being sent, so a slow
+/// or unreachable InfluxDB instance can't make the measurement pipeline back up indefinitely.
+const DROP_DEADLINE: Duration = Duration::from_secs(30);
+const MAX_SEND_ATTEMPTS: u32 = 3;
+/// Per-request timeout. `reqwest::Client`'s default has none, so without this a connection that
+/// accepts the TCP handshake and then never responds would hang each retry attempt indefinitely,
+/// defeating `DROP_DEADLINE`.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the `Client` shared by every InfluxDB write, with `REQUEST_TIMEOUT` applied.
+pub fn build_client() -> Client {
+    Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("failed to build reqwest client")
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldValue {
+    FloatValue(f64),
+    IntegerValue(i64),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::FloatValue(value) => write!(f, "{}", value),
+            FieldValue::IntegerValue(value) => write!(f, "{}i", value),
+        }
+    }
+}
+
+impl FieldValue {
+    /// Numeric value without the InfluxDB line-protocol integer suffix, for formats (like the
+    /// Prometheus exposition format) that don't distinguish integer and float fields.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            FieldValue::FloatValue(value) => *value,
+            FieldValue::IntegerValue(value) => *value as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DataPoint {
+    pub measurement: String,
+    pub tag_set: BTreeMap<String, String>,
+    pub field_set: BTreeMap<String, FieldValue>,
+    pub timestamp: Option<SystemTime>,
+}
+
+impl fmt::Display for DataPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.measurement)?;
+        for (key, value) in &self.tag_set {
+            write!(f, ",{}={}", key, value)?;
+        }
+        write!(f, " ")?;
+        let mut first = true;
+        for (key, value) in &self.field_set {
+            // InfluxDB line protocol rejects non-finite floats outright, so drop those fields
+            // rather than emit a line the server will just reject.
+            if let FieldValue::FloatValue(float_value) = value {
+                if !float_value.is_finite() {
+                    continue;
+                }
+            }
+            if !first {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+            first = false;
+        }
+        if let Some(timestamp) = self.timestamp {
+            let nanos = timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            write!(f, " {}", nanos)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connection and batching settings for the background InfluxDB writer.
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    pub url: String,
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+struct PendingLine {
+    line: String,
+    enqueued_at: Instant,
+}
+
+/// Spawns the background task that batches line-protocol strings and flushes them to InfluxDB,
+/// and returns the channel used to feed it. One `Client` and one task are shared by every
+/// measurement instead of creating a new client and request per data point.
+pub fn spawn_influx_writer(client: Client, config: InfluxConfig) -> Sender<String> {
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::spawn(run_writer(client, config, rx));
+    tx
+}
+
+async fn run_writer(client: Client, config: InfluxConfig, mut rx: mpsc::Receiver<String>) {
+    let mut buffer: Vec<PendingLine> = Vec::with_capacity(config.batch_size);
+    let mut ticker = time::interval(config.flush_interval);
+    loop {
+        tokio::select! {
+            received = rx.recv() => {
+                match received {
+                    Some(line) => {
+                        buffer.push(PendingLine {
+                            line,
+                            enqueued_at: Instant::now(),
+                        });
+                        if buffer.len() >= config.batch_size {
+                            flush(&client, &config, &mut buffer).await;
+                        }
+                    }
+                    None => {
+                        flush(&client, &config, &mut buffer).await;
+                        return;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                flush(&client, &config, &mut buffer).await;
+            }
+        }
+    }
+}
+
+async fn flush(client: &Client, config: &InfluxConfig, buffer: &mut Vec<PendingLine>) {
+    if buffer.is_empty() {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut dropped = 0;
+    let lines: Vec<&str> = buffer
+        .iter()
+        .filter(|pending| {
+            let fresh = now.duration_since(pending.enqueued_at) < DROP_DEADLINE;
+            if !fresh {
+                dropped += 1;
+            }
+            fresh
+        })
+        .map(|pending| pending.line.as_str())
+        .collect();
+
+    if dropped > 0 {
+        eprintln!(
+            "warning: dropped {} influxdb point(s) older than {:?}",
+            dropped, DROP_DEADLINE
+        );
+    }
+
+    if !lines.is_empty() {
+        let body = lines.join("\n");
+        if let Err(error) = post_with_retry(client, config, body).await {
+            eprintln!(
+                "error: giving up after {} attempts, dropping batch of {} point(s): {}",
+                MAX_SEND_ATTEMPTS,
+                lines.len(),
+                error
+            );
+        }
+    }
+
+    buffer.clear();
+}
+
+/// Terminal failure of `post_with_retry`, after `MAX_SEND_ATTEMPTS` have all failed.
+#[derive(Debug)]
+enum WriteError {
+    /// The request never got a response (connection refused, timed out, ...).
+    Transport(reqwest::Error),
+    /// InfluxDB responded, but rejected the batch (bad auth token, malformed line, 5xx, ...).
+    Status(reqwest::StatusCode),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::Transport(error) => write!(f, "{}", error),
+            WriteError::Status(status) => write!(f, "server responded with status {}", status),
+        }
+    }
+}
+
+async fn post_with_retry(
+    client: &Client,
+    config: &InfluxConfig,
+    body: String,
+) -> Result<(), WriteError> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let request = authenticated_request(client.post(&config.url), config);
+        match request.body(body.clone()).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                if attempt >= MAX_SEND_ATTEMPTS {
+                    return Err(WriteError::Status(status));
+                }
+                eprintln!(
+                    "warning: influxdb write attempt {} failed with status {}",
+                    attempt, status
+                );
+            }
+            Err(error) => {
+                if attempt >= MAX_SEND_ATTEMPTS {
+                    return Err(WriteError::Transport(error));
+                }
+                eprintln!(
+                    "warning: influxdb write attempt {} failed: {}",
+                    attempt, error
+                );
+            }
+        }
+        time::sleep(Duration::from_millis(200 * attempt as u64)).await;
+    }
+}
+
+fn authenticated_request(request: RequestBuilder, config: &InfluxConfig) -> RequestBuilder {
+    if let Some(token) = &config.token {
+        request.header("Authorization", format!("Token {}", token))
+    } else if let Some(user) = &config.user {
+        request.basic_auth(user, config.password.as_ref())
+    } else {
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_point(fields: Vec<(&str, FieldValue)>) -> DataPoint {
+        let mut field_set = BTreeMap::new();
+        for (key, value) in fields {
+            field_set.insert(key.to_string(), value);
+        }
+        DataPoint {
+            measurement: "ruuvi_measurements".to_string(),
+            tag_set: BTreeMap::new(),
+            field_set,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn non_finite_float_fields_are_dropped() {
+        let point = data_point(vec![
+            ("temperature", FieldValue::FloatValue(21.5)),
+            ("dewPoint", FieldValue::FloatValue(f64::NAN)),
+            ("pressure", FieldValue::FloatValue(f64::INFINITY)),
+        ]);
+        assert_eq!(point.to_string(), "ruuvi_measurements temperature=21.5");
+    }
+
+    #[test]
+    fn finite_fields_are_kept_and_comma_separated() {
+        let point = data_point(vec![
+            ("humidity", FieldValue::FloatValue(55.2)),
+            ("rssi", FieldValue::IntegerValue(-70)),
+        ]);
+        assert_eq!(
+            point.to_string(),
+            "ruuvi_measurements humidity=55.2,rssi=-70i"
+        );
+    }
+
+    #[test]
+    fn negative_infinity_is_dropped_too() {
+        let point = data_point(vec![("pressure", FieldValue::FloatValue(f64::NEG_INFINITY))]);
+        assert_eq!(point.to_string(), "ruuvi_measurements ");
+    }
+
+    #[test]
+    fn all_non_finite_fields_leaves_an_empty_field_set() {
+        let point = data_point(vec![
+            ("dewPoint", FieldValue::FloatValue(f64::NAN)),
+            ("pressure", FieldValue::FloatValue(f64::INFINITY)),
+        ]);
+        assert_eq!(point.to_string(), "ruuvi_measurements ");
+    }
+
+    #[test]
+    fn integer_fields_are_never_filtered() {
+        let point = data_point(vec![("rssi", FieldValue::IntegerValue(-70))]);
+        assert_eq!(point.to_string(), "ruuvi_measurements rssi=-70i");
+    }
+}
@@ -4,22 +4,25 @@ extern crate ruuvi_sensor_protocol;
 
 use crate::ruuvi_sensor_protocol::{
     Acceleration, BatteryPotential, Co2, Humidity, MeasurementSequenceNumber, MovementCounter,
-    Pm25, Pressure, Temperature, TransmitterPower, DataFormat, AirDensity,
+    ParseError, Pm25, Pressure, Temperature, TransmitterPower, DataFormat, AirDensity,
 };
+use btleplug::api::BDAddr;
 use clap::Parser;
 use std::collections::BTreeMap;
 use std::io::Write;
 use std::panic::{self, PanicHookInfo};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 pub mod ruuvi;
-use ruuvi::{on_measurement, Measurement};
+use ruuvi::{on_measurement, Filter, Measurement, MeasurementStream};
 
 pub mod influxdb;
-use influxdb::{DataPoint, FieldValue};
+use influxdb::{build_client, spawn_influx_writer, DataPoint, FieldValue, InfluxConfig};
+
+pub mod prometheus;
+use prometheus::{spawn_prometheus_exporter, PrometheusState};
 
-use crate::influxdb::write_line_to_influx;
 use btleplug::Error::PermissionDenied;
-use reqwest::Client;
+use tokio::sync::mpsc::Sender;
 
 fn tag_set(
     aliases: &BTreeMap<String, String>,
@@ -245,6 +248,16 @@ fn alias_map(aliases: &[Alias]) -> BTreeMap<String, String> {
     map
 }
 
+/// Converts a `--*-mac-allowlist` option into the `Filter` representation, where an empty list
+/// means "no restriction" rather than "match nothing".
+fn mac_allowlist(addresses: &[BDAddr]) -> Option<Vec<BDAddr>> {
+    if addresses.is_empty() {
+        None
+    } else {
+        Some(addresses.to_vec())
+    }
+}
+
 // Note! Some breaking changes done:
 // - default value for influxdb_measurement has been changed slightly
 // - added option to *keep* the colons in mac address. So by default the colons in mac address
@@ -269,60 +282,168 @@ struct Options {
     /// If empty, all versions are handled.
     #[clap(long = "ruuvi-data-format-versions", use_value_delimiter = true)]
     data_format_versions: Vec<u8>,
+    /// InfluxDB write endpoint that batches of line protocol data are POSTed to.
+    #[clap(long, default_value = "http://localhost:8086/write?db=ruuvi")]
+    influxdb_url: String,
+    /// Number of points to accumulate before flushing a batch to InfluxDB.
+    #[clap(long, default_value = "20")]
+    influxdb_batch_size: usize,
+    /// Maximum time in milliseconds to wait before flushing a partial batch to InfluxDB.
+    #[clap(long, default_value = "1000")]
+    influxdb_flush_interval_ms: u64,
+    /// InfluxDB API token, sent as an `Authorization: Token <value>` header. Takes precedence
+    /// over --influxdb-user/--influxdb-password.
+    #[clap(long)]
+    influxdb_token: Option<String>,
+    /// Username for InfluxDB HTTP basic auth.
+    #[clap(long)]
+    influxdb_user: Option<String>,
+    /// Password for InfluxDB HTTP basic auth.
+    #[clap(long)]
+    influxdb_password: Option<String>,
+    /// Decode RuuviTag frames directly from advertisement events instead of querying each
+    /// peripheral's properties. Lowers per-event latency and CPU when many tags are in range, at
+    /// the cost of RSSI/tx power no longer being available. Falls back to querying properties
+    /// for a device until its manufacturer data has arrived as a standalone advertisement event.
+    /// This does not change the scan request issued to the adapter (btleplug has no passive/
+    /// active toggle), so it does not reduce RuuviTag scan-response battery use.
+    #[clap(long)]
+    passive: bool,
+    /// Bluetooth adapter to use, given either as its index or its name as shown when no
+    /// adapter matches. Defaults to the first available adapter.
+    #[clap(long)]
+    adapter: Option<String>,
+    /// Address (host:port) to serve a Prometheus/OpenMetrics `/metrics` endpoint on, e.g.
+    /// `0.0.0.0:9185`. Exposes the latest reading from every seen RuuviTag as gauges, in
+    /// addition to the InfluxDB push. Disabled by default.
+    #[clap(long)]
+    prometheus_listen: Option<String>,
+    /// Restrict stdout output to these RuuviTag MAC addresses (comma separated). If empty, every
+    /// matching measurement is printed.
+    #[clap(long, use_value_delimiter = true)]
+    stdout_mac_allowlist: Vec<BDAddr>,
+    /// Restrict InfluxDB writes to these RuuviTag MAC addresses (comma separated). If empty,
+    /// every matching measurement is written.
+    #[clap(long, use_value_delimiter = true)]
+    influxdb_mac_allowlist: Vec<BDAddr>,
+    /// Restrict the Prometheus exporter to these RuuviTag MAC addresses (comma separated). If
+    /// empty, every matching measurement is exposed.
+    #[clap(long, use_value_delimiter = true)]
+    prometheus_mac_allowlist: Vec<BDAddr>,
 }
 
-async fn print_result_async(
-    aliases: &BTreeMap<String, String>,
-    name: &str,
-    measurement: Measurement,
-    http_client: Option<&Client>,
-    options: &Options,
-) {
-    if options
-        .data_format_versions
-        .contains(&measurement.sensor_values.get_dataformat().unwrap())
-        || options.data_format_versions.is_empty()
-    {
-        let datapoint = to_data_point(&aliases, name.to_string(), &measurement, options);
-        match writeln!(std::io::stdout(), "{datapoint}",) {
-            Ok(_) => (),
-            Err(error) => {
+// Prints every matching measurement to stdout as a line-protocol-formatted point, and any parse
+// error when running verbosely. This is the subscriber that used to be inlined into the single
+// callback `on_measurement` invoked for every event.
+fn stdout_subscriber(
+    aliases: BTreeMap<String, String>,
+    name: String,
+    verbose: bool,
+    options: Options,
+) -> Box<dyn Fn(Result<Measurement, ParseError>) + Send + Sync> {
+    Box::new(move |result| match result {
+        Ok(measurement) => {
+            let datapoint = to_data_point(&aliases, name.clone(), &measurement, &options);
+            if let Err(error) = writeln!(std::io::stdout(), "{datapoint}") {
                 eprintln!("error: {}", error);
                 ::std::process::exit(1);
             }
         }
-
-        match http_client {
-            Some(client) => {
-                write_line_to_influx(client, datapoint.to_string()).await;
-            }
-            None => {
-                println!("No http client set!");
-                ::std::process::exit(1);
+        Err(error) => {
+            if verbose {
+                eprintln!("{}", error)
             }
         }
-    }
+    })
 }
 
-#[tokio::main]
-async fn listen(options: Options) -> Result<(), btleplug::Error> {
-    let verbose = options.verbose;
-    on_measurement(Box::new(move |result| match result {
-        Ok(measurement) => {
-            let name = options.influxdb_measurement.clone();
-            let client = Client::new();
-            let opt = options.clone();
-            let aliases = alias_map(&options.alias);
+// Batches every matching measurement off to the InfluxDB writer task.
+fn influx_subscriber(
+    aliases: BTreeMap<String, String>,
+    name: String,
+    influx_tx: Sender<String>,
+    options: Options,
+) -> Box<dyn Fn(Result<Measurement, ParseError>) + Send + Sync> {
+    Box::new(move |result| {
+        if let Ok(measurement) = result {
+            let datapoint = to_data_point(&aliases, name.clone(), &measurement, &options);
+            let influx_tx = influx_tx.clone();
             tokio::spawn(async move {
-                print_result_async(&aliases, &name, measurement, Some(&client), &opt).await;
+                if influx_tx.send(datapoint.to_string()).await.is_err() {
+                    eprintln!("error: influxdb writer task is no longer running");
+                }
             });
         }
-        Err(error) => {
-            if verbose {
-                eprintln!("{}", error)
-            }
+    })
+}
+
+// Updates the Prometheus exporter's last-known-value map for every matching measurement.
+fn prometheus_subscriber(
+    aliases: BTreeMap<String, String>,
+    state: PrometheusState,
+    options: Options,
+) -> Box<dyn Fn(Result<Measurement, ParseError>) + Send + Sync> {
+    Box::new(move |result| {
+        if let Ok(measurement) = result {
+            state.update(
+                measurement.address,
+                tag_set(&aliases, &measurement, &options),
+                field_set(&measurement),
+            );
         }
-    })).await
+    })
+}
+
+fn influx_config(options: &Options) -> InfluxConfig {
+    InfluxConfig {
+        url: options.influxdb_url.clone(),
+        token: options.influxdb_token.clone(),
+        user: options.influxdb_user.clone(),
+        password: options.influxdb_password.clone(),
+        batch_size: options.influxdb_batch_size,
+        flush_interval: Duration::from_millis(options.influxdb_flush_interval_ms),
+    }
+}
+
+#[tokio::main]
+async fn listen(options: Options) -> Result<(), btleplug::Error> {
+    let passive = options.passive;
+    let adapter_selector = options.adapter.clone();
+    let influx_tx = spawn_influx_writer(build_client(), influx_config(&options));
+    let prometheus_state = options.prometheus_listen.clone().map(spawn_prometheus_exporter);
+
+    // Every backend shares the alias map and `--ruuvi-data-format-versions`, but each gets its
+    // own `--*-mac-allowlist`, so e.g. a noisy tag can be kept out of Prometheus while still
+    // being written to InfluxDB.
+    let stdout_filter = Filter {
+        mac_allowlist: mac_allowlist(&options.stdout_mac_allowlist),
+        data_format_versions: options.data_format_versions.clone(),
+    };
+    let influx_filter = Filter {
+        mac_allowlist: mac_allowlist(&options.influxdb_mac_allowlist),
+        data_format_versions: options.data_format_versions.clone(),
+    };
+    let prometheus_filter = Filter {
+        mac_allowlist: mac_allowlist(&options.prometheus_mac_allowlist),
+        data_format_versions: options.data_format_versions.clone(),
+    };
+    let aliases = alias_map(&options.alias);
+    let name = options.influxdb_measurement.clone();
+
+    let mut stream = MeasurementStream::new()
+        .subscribe(
+            stdout_filter,
+            stdout_subscriber(aliases.clone(), name.clone(), options.verbose, options.clone()),
+        )
+        .subscribe(
+            influx_filter,
+            influx_subscriber(aliases.clone(), name, influx_tx, options.clone()),
+        );
+    if let Some(state) = prometheus_state {
+        stream = stream.subscribe(prometheus_filter, prometheus_subscriber(aliases, state, options));
+    }
+
+    on_measurement(adapter_selector, passive, stream).await
 }
 
 fn main() {
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use btleplug::api::BDAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::influxdb::FieldValue;
+
+/// Prometheus metric name and HELP text for one `field_set` key. The unit is baked into the
+/// metric name, following Prometheus naming conventions (e.g. `_celsius`, `_pascals`).
+struct MetricInfo {
+    name: &'static str,
+    help: &'static str,
+}
+
+/// Maps a `field_set` key to the Prometheus series it is exposed as. Kept in the same order as
+/// `field_set` populates its map, purely so `/metrics` output reads in a predictable order.
+const METRICS: &[(&str, MetricInfo)] = &[
+    (
+        "temperature",
+        MetricInfo {
+            name: "ruuvi_temperature_celsius",
+            help: "Air temperature in degrees Celsius.",
+        },
+    ),
+    (
+        "dewPoint",
+        MetricInfo {
+            name: "ruuvi_dew_point_celsius",
+            help: "Dew point in degrees Celsius.",
+        },
+    ),
+    (
+        "humidity",
+        MetricInfo {
+            name: "ruuvi_humidity_ratio",
+            help: "Relative humidity as a 0-1 ratio.",
+        },
+    ),
+    (
+        "absoluteHumidity",
+        MetricInfo {
+            name: "ruuvi_absolute_humidity_grams_per_cubic_meter",
+            help: "Absolute humidity in grams per cubic meter.",
+        },
+    ),
+    (
+        "pressure",
+        MetricInfo {
+            name: "ruuvi_pressure_pascals",
+            help: "Air pressure in pascals.",
+        },
+    ),
+    (
+        "batteryVoltage",
+        MetricInfo {
+            name: "ruuvi_battery_volts",
+            help: "Battery voltage in volts.",
+        },
+    ),
+    (
+        "txPower",
+        MetricInfo {
+            name: "ruuvi_tx_power_dbm",
+            help: "Last advertised transmit power in dBm.",
+        },
+    ),
+    (
+        "movementCounter",
+        MetricInfo {
+            name: "ruuvi_movement_counter",
+            help: "Number of movements detected by the accelerometer since startup.",
+        },
+    ),
+    (
+        "measurementSequenceNumber",
+        MetricInfo {
+            name: "ruuvi_measurement_sequence_number",
+            help: "Sequence number of the measurement, incremented every time a new measurement is sent.",
+        },
+    ),
+    (
+        "pm25",
+        MetricInfo {
+            name: "ruuvi_pm25_micrograms_per_cubic_meter",
+            help: "PM2.5 concentration in micrograms per cubic meter.",
+        },
+    ),
+    (
+        "co2",
+        MetricInfo {
+            name: "ruuvi_co2_ppm",
+            help: "CO2 concentration in parts per million.",
+        },
+    ),
+    (
+        "dataFormat",
+        MetricInfo {
+            name: "ruuvi_data_format",
+            help: "Ruuvi Sensor Data format version of the last measurement.",
+        },
+    ),
+    (
+        "rssi",
+        MetricInfo {
+            name: "ruuvi_rssi_dbm",
+            help: "Received signal strength indicator in dBm.",
+        },
+    ),
+    (
+        "airDensity",
+        MetricInfo {
+            name: "ruuvi_air_density_kg_per_cubic_meter",
+            help: "Air density in kilograms per cubic meter.",
+        },
+    ),
+    (
+        "equilibriumVaporPressure",
+        MetricInfo {
+            name: "ruuvi_equilibrium_vapor_pressure_hpa",
+            help: "Saturation vapor pressure in hectopascals.",
+        },
+    ),
+    (
+        "accelerationX",
+        MetricInfo {
+            name: "ruuvi_acceleration_x_g",
+            help: "Acceleration along the X axis in g.",
+        },
+    ),
+    (
+        "accelerationY",
+        MetricInfo {
+            name: "ruuvi_acceleration_y_g",
+            help: "Acceleration along the Y axis in g.",
+        },
+    ),
+    (
+        "accelerationZ",
+        MetricInfo {
+            name: "ruuvi_acceleration_z_g",
+            help: "Acceleration along the Z axis in g.",
+        },
+    ),
+];
+
+/// Latest known tag set and field set for one RuuviTag, as produced by `tag_set`/`field_set`.
+struct Snapshot {
+    tags: BTreeMap<String, String>,
+    fields: BTreeMap<String, FieldValue>,
+}
+
+/// Handle to the background `/metrics` HTTP server. Cloning shares the same last-known-value
+/// map, so every measurement callback can update it independently of the InfluxDB writer.
+#[derive(Clone)]
+pub struct PrometheusState {
+    snapshots: Arc<Mutex<BTreeMap<BDAddr, Snapshot>>>,
+}
+
+impl PrometheusState {
+    /// Records the latest tag set and field set seen for `address`, overwriting any previous
+    /// reading.
+    pub fn update(
+        &self,
+        address: BDAddr,
+        tags: BTreeMap<String, String>,
+        fields: BTreeMap<String, FieldValue>,
+    ) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.insert(address, Snapshot { tags, fields });
+    }
+
+    /// Renders every known RuuviTag's last reading as OpenMetrics/Prometheus text exposition
+    /// format, grouping all series for a metric under one `# HELP`/`# TYPE` pair.
+    fn render(&self) -> String {
+        let snapshots = self.snapshots.lock().unwrap();
+        let mut body = String::new();
+        for (key, info) in METRICS {
+            let series: Vec<String> = snapshots
+                .values()
+                .filter_map(|snapshot| {
+                    let value = snapshot.fields.get(*key)?.as_f64();
+                    // `field_set` stores humidity in percent, pressure in kilopascals and vapor
+                    // pressure in pascals; convert them to the units the metric names carry.
+                    let value = match *key {
+                        "humidity" => value / 100.0,
+                        "pressure" => value * 1000.0,
+                        "equilibriumVaporPressure" => value / 100.0,
+                        _ => value,
+                    };
+                    let mac = snapshot.tags.get("mac").map(String::as_str).unwrap_or("");
+                    let name = snapshot.tags.get("name").map(String::as_str).unwrap_or("");
+                    Some(format!(
+                        "{}{{mac=\"{}\",name=\"{}\"}} {}",
+                        info.name,
+                        escape_label_value(mac),
+                        escape_label_value(name),
+                        value
+                    ))
+                })
+                .collect();
+            if series.is_empty() {
+                continue;
+            }
+            body.push_str(&format!("# HELP {} {}\n", info.name, info.help));
+            body.push_str(&format!("# TYPE {} gauge\n", info.name));
+            for line in series {
+                body.push_str(&line);
+                body.push('\n');
+            }
+        }
+        body
+    }
+}
+
+/// Escapes a label value per the Prometheus text exposition format, so a `mac`/`name` containing
+/// a backslash, double quote, or newline (e.g. from `--alias`) can't break the scrape for every
+/// metric on the line.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_label_value_escapes_backslash_quote_and_newline() {
+        assert_eq!(escape_label_value(r"back\slash"), r"back\\slash");
+        assert_eq!(escape_label_value("quo\"te"), "quo\\\"te");
+        assert_eq!(escape_label_value("new\nline"), "new\\nline");
+    }
+
+    #[test]
+    fn render_escapes_an_alias_containing_injection_characters() {
+        let state = PrometheusState {
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+        let address: BDAddr = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        let mut tags = BTreeMap::new();
+        tags.insert("mac".to_string(), address.to_string());
+        tags.insert("name".to_string(), "Sauna\" } ruuvi_injected 1\n#".to_string());
+        let mut fields = BTreeMap::new();
+        fields.insert("temperature".to_string(), FieldValue::FloatValue(21.5));
+        state.update(address, tags, fields);
+
+        let body = state.render();
+        assert!(body.contains(r#"name="Sauna\" } ruuvi_injected 1\n#""#));
+        assert!(!body.contains("name=\"Sauna\" }"));
+    }
+}
+
+/// Spawns the `/metrics` HTTP server used by a Prometheus scrape-based monitoring stack and
+/// returns the handle that measurement callbacks feed. `listen_addr` is a `host:port` pair, e.g.
+/// `0.0.0.0:9185`.
+pub fn spawn_prometheus_exporter(listen_addr: String) -> PrometheusState {
+    let state = PrometheusState {
+        snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+    };
+    let server_state = state.clone();
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&listen_addr).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                eprintln!(
+                    "error: failed to bind prometheus listener on {}: {}",
+                    listen_addr, error
+                );
+                return;
+            }
+        };
+        eprintln!("serving prometheus metrics on http://{}/metrics", listen_addr);
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    tokio::spawn(serve_connection(socket, server_state.clone()));
+                }
+                Err(error) => {
+                    eprintln!("warning: failed to accept prometheus connection: {}", error);
+                }
+            }
+        }
+    });
+    state
+}
+
+/// Handles one HTTP/1.1 request just well enough to serve `GET /metrics`, since this exporter
+/// doesn't need a general-purpose HTTP stack.
+async fn serve_connection(mut socket: TcpStream, state: PrometheusState) {
+    let mut buffer = [0u8; 1024];
+    let n = match socket.read(&mut buffer).await {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let request = String::from_utf8_lossy(&buffer[..n]);
+    let request_line = request.lines().next().unwrap_or("");
+
+    let response = if request_line.starts_with("GET /metrics") {
+        let body = state.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        let body = "not found\n";
+        format!(
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    };
+
+    let _ = socket.write_all(response.as_bytes()).await;
+}
@@ -3,9 +3,15 @@ use btleplug::platform::{Adapter, PeripheralId};
 use ruuvi_sensor_protocol::{ParseError, SensorValues};
 use btleplug::api;
 use futures::stream::StreamExt;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bound on the exponential backoff between reconnect attempts.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
 
 // Measurement from RuuviTag sensor
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Measurement {
     pub address: BDAddr,
     pub tx_power: Option<i16>,
@@ -68,45 +74,416 @@ async fn on_event_with_address(
     }
 }
 
+// Decode manufacturer data delivered directly on the advertisement event, without a
+// peripheral().await/properties().await round-trip. The BDAddr is recovered from the
+// PeripheralId's string form: WinRT formats it as a bare MAC address, and BlueZ (the backend
+// this crate actually ships against) formats it as a D-Bus object path ending in
+// `dev_AA_BB_CC_DD_EE_FF` (see bluez_async::DeviceId::fmt); both are parsed without touching the
+// adapter. CoreBluetooth's PeripheralId is an opaque UUID with no MAC embedded in it, so that
+// backend always falls through to resolving it through the adapter like the active-scan path
+// does.
+/// Parses a `PeripheralId`'s string form as a MAC address: either a bare address (WinRT) or a
+/// BlueZ D-Bus object path ending in `dev_AA_BB_CC_DD_EE_FF`. Split out from
+/// `on_manufacturer_data_advertisement` so the parsing is unit-testable without a live `Adapter`.
+fn parse_inline_address(id: &str) -> Option<BDAddr> {
+    if let Ok(address) = id.parse() {
+        return Some(address);
+    }
+    let suffix = id.rsplit('/').next()?.strip_prefix("dev_")?;
+    suffix.replace('_', ":").parse().ok()
+}
+
+async fn on_manufacturer_data_advertisement(
+    central: &Adapter,
+    id: &PeripheralId,
+    manufacturer_data: &std::collections::HashMap<u16, Vec<u8>>,
+) -> Option<Result<Measurement, ParseError>> {
+    if !manufacturer_data.contains_key(&MANUFACTURER_DATA_ID) {
+        return None;
+    }
+
+    let address = match parse_inline_address(&id.to_string()) {
+        Some(address) => address,
+        None => central.peripheral(id).await.ok()?.address(),
+    };
+
+    match from_manufacturer_data(&manufacturer_data[&MANUFACTURER_DATA_ID]) {
+        Ok(sensor_values) => Some(Ok(Measurement {
+            address,
+            // Neither RSSI nor tx power is carried on this event; they stay unset rather than
+            // paying for a properties() round-trip just to fill them in.
+            rssi: None,
+            tx_power: None,
+            sensor_values,
+        })),
+        Err(error) => Some(Err(error)),
+    }
+}
+
+#[cfg(test)]
+mod inline_address_tests {
+    use super::*;
+
+    #[test]
+    fn parse_inline_address_accepts_mac_formatted_ids() {
+        let address: BDAddr = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        assert_eq!(parse_inline_address("AA:BB:CC:DD:EE:FF"), Some(address));
+    }
+
+    #[test]
+    fn parse_inline_address_rejects_non_mac_ids() {
+        assert_eq!(parse_inline_address("not-a-mac-address"), None);
+    }
+
+    #[test]
+    fn parse_inline_address_accepts_bluez_device_object_paths() {
+        let address: BDAddr = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        assert_eq!(
+            parse_inline_address("hci0/dev_AA_BB_CC_DD_EE_FF"),
+            Some(address)
+        );
+    }
+
+    #[test]
+    fn parse_inline_address_rejects_corebluetooth_style_uuids() {
+        assert_eq!(
+            parse_inline_address("12345678-1234-1234-1234-123456789abc"),
+            None
+        );
+    }
+}
+
+// In passive mode, `DeviceDiscovered`/`DeviceUpdated` only fall back to the
+// peripheral()/properties() round-trip for ids that `seen_inline` has no record of having
+// produced a `ManufacturerDataAdvertisement` for yet - e.g. a backend that never emits that event
+// for a given device. Once an id has delivered manufacturer data inline, later
+// `DeviceDiscovered`/`DeviceUpdated` events for it are ignored so the round-trip isn't paid twice.
 async fn on_event(
     central: &Adapter,
     event: CentralEvent,
+    passive: bool,
+    seen_inline: &mut HashSet<PeripheralId>,
 ) -> Option<Result<Measurement, ParseError>> {
     match event {
-        CentralEvent::DeviceDiscovered(address) => { on_event_with_address(central, &address).await },
-        CentralEvent::DeviceUpdated(address) => on_event_with_address(central, &address).await,
+        CentralEvent::DeviceDiscovered(address) => {
+            if passive {
+                if seen_inline.contains(&address) {
+                    None
+                } else {
+                    on_event_with_address(central, &address).await
+                }
+            } else {
+                on_event_with_address(central, &address).await
+            }
+        }
+        CentralEvent::DeviceUpdated(address) => {
+            if passive {
+                if seen_inline.contains(&address) {
+                    None
+                } else {
+                    on_event_with_address(central, &address).await
+                }
+            } else {
+                on_event_with_address(central, &address).await
+            }
+        }
         CentralEvent::DeviceConnected(_) => None,
         CentralEvent::DeviceDisconnected(_) => None,
-        CentralEvent::ManufacturerDataAdvertisement { .. } => {None}
+        CentralEvent::ManufacturerDataAdvertisement { id, manufacturer_data } => {
+            if passive {
+                seen_inline.insert(id.clone());
+                on_manufacturer_data_advertisement(central, &id, &manufacturer_data).await
+            } else {
+                None
+            }
+        }
         CentralEvent::ServiceDataAdvertisement { .. } => {None}
         CentralEvent::ServicesAdvertisement { .. } => {None}
         CentralEvent::StateUpdate(_) => {None}
     }
 }
 
-// Stream of RuuviTag measurements that gets passed to the given callback. Blocks and never stops.
-pub async fn on_measurement(
-    f: Box<dyn Fn(Result<Measurement, ParseError>) + Send>,
-) -> Result<(), btleplug::Error> {
-    let manager : btleplug::platform::Manager = btleplug::platform::Manager::new().await?;
+// Picks an adapter matching `selector` (an index into `adapters`, or a name matching
+// `AdapterInfo`), or the first available adapter when `selector` is `None`. Lists the available
+// adapters when a selector was given but nothing matched.
+async fn select_adapter(
+    adapters: &[Adapter],
+    selector: Option<&str>,
+) -> Option<Adapter> {
+    match selector {
+        None => adapters.first().cloned(),
+        Some(selector) => {
+            let mut names = Vec::with_capacity(adapters.len());
+            for adapter in adapters {
+                names.push(adapter.adapter_info().await.ok());
+            }
+            if let Some(index) = resolve_adapter_selector(&names, selector) {
+                return Some(adapters[index].clone());
+            }
+            eprintln!("error: no bluetooth adapter matches '{}'. Available adapters:", selector);
+            for (index, name) in names.iter().enumerate() {
+                eprintln!("  [{}] {}", index, name.as_deref().unwrap_or("<unknown>"));
+            }
+            None
+        }
+    }
+}
+
+/// Resolves `selector` against `names` (the `adapter_info()` result for each adapter, in the
+/// same order as `adapters`; `None` where that call failed), by index first and then by exact
+/// name match. Split out from `select_adapter` so the resolution logic is unit-testable without
+/// a live `Adapter`.
+fn resolve_adapter_selector(names: &[Option<String>], selector: &str) -> Option<usize> {
+    if let Ok(index) = selector.parse::<usize>() {
+        if index < names.len() {
+            return Some(index);
+        }
+    }
+    names.iter().position(|name| name.as_deref() == Some(selector))
+}
 
-    // get bluetooth adapter
-    let adapters = manager.adapters().await?;
+#[cfg(test)]
+mod adapter_selection_tests {
+    use super::*;
+
+    #[test]
+    fn resolve_adapter_selector_matches_by_in_bounds_index() {
+        let names = vec![Some("hci0".to_string()), Some("hci1".to_string())];
+        assert_eq!(resolve_adapter_selector(&names, "1"), Some(1));
+    }
 
-    let adapter : Adapter = adapters
-        .into_iter()
-        .next()
-        .expect("Bluetooth adapter not available");
+    #[test]
+    fn resolve_adapter_selector_falls_back_to_name_when_index_out_of_bounds() {
+        let names = vec![Some("hci0".to_string())];
+        assert_eq!(resolve_adapter_selector(&names, "5"), None);
+        assert_eq!(resolve_adapter_selector(&names, "hci0"), Some(0));
+    }
+
+    #[test]
+    fn resolve_adapter_selector_ignores_adapters_with_unknown_name() {
+        let names = vec![None, Some("hci1".to_string())];
+        assert_eq!(resolve_adapter_selector(&names, "hci1"), Some(1));
+        assert_eq!(resolve_adapter_selector(&names, "<unknown>"), None);
+    }
 
+    #[test]
+    fn resolve_adapter_selector_returns_none_when_nothing_matches() {
+        let names = vec![Some("hci0".to_string())];
+        assert_eq!(resolve_adapter_selector(&names, "hci9"), None);
+    }
+}
+
+// Waits, with capped exponential backoff, until an adapter matching `selector` is available.
+// This lets the listener come up cleanly on a headless boot where Bluetooth isn't ready yet,
+// or recover after a USB dongle is unplugged and replugged, instead of panicking once at
+// startup.
+async fn wait_for_adapter(
+    manager: &btleplug::platform::Manager,
+    selector: Option<&str>,
+) -> Adapter {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match manager.adapters().await {
+            Ok(adapters) => {
+                if let Some(adapter) = select_adapter(&adapters, selector).await {
+                    return adapter;
+                }
+            }
+            Err(error) => {
+                eprintln!("warning: failed to list bluetooth adapters: {}", error);
+            }
+        }
+        eprintln!("waiting {:?} for a bluetooth adapter to become available", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
+
+// Runs one scan session to completion: starts the scan and fans out every decoded measurement
+// through `stream` until the event stream ends. Returns whether at least one event was
+// delivered, so the caller can decide whether to reset its reconnect backoff.
+//
+// Dispatch is handed off to a spawned task per event instead of being awaited inline, so a slow
+// subscriber (e.g. a blocked stdout pipe) can't stall the adapter's event stream and delay
+// delivery to every other subscriber.
+async fn run_scan(
+    adapter: &Adapter,
+    passive: bool,
+    stream: &Arc<MeasurementStream>,
+) -> Result<bool, btleplug::Error> {
     let mut events = adapter.events().await?;
 
     adapter.start_scan(ScanFilter::default()).await?;
 
+    let mut delivered_any = false;
+    let mut seen_inline = HashSet::new();
     while let Some(event) = events.next().await {
-        if let Some(result) = on_event(&adapter, event).await {
-            f(result)
+        if let Some(result) = on_event(adapter, event, passive, &mut seen_inline).await {
+            delivered_any = true;
+            let stream = stream.clone();
+            tokio::spawn(async move { stream.dispatch(result) });
+        }
+    }
+
+    Ok(delivered_any)
+}
+
+/// Criteria deciding whether a decoded measurement is delivered to one subscriber. An empty
+/// `data_format_versions` matches every format (the default), mirroring how
+/// `--ruuvi-data-format-versions` behaves when left unset; `mac_allowlist` of `None` matches
+/// every address.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub mac_allowlist: Option<Vec<BDAddr>>,
+    pub data_format_versions: Vec<u8>,
+}
+
+impl Filter {
+    fn matches(&self, measurement: &Measurement) -> bool {
+        Self::mac_matches(self.mac_allowlist.as_deref(), measurement.address)
+            && Self::format_matches(
+                &self.data_format_versions,
+                measurement.sensor_values.get_dataformat(),
+            )
+    }
+
+    fn mac_matches(allowlist: Option<&[BDAddr]>, address: BDAddr) -> bool {
+        allowlist
+            .map(|allowlist| allowlist.contains(&address))
+            .unwrap_or(true)
+    }
+
+    fn format_matches(data_format_versions: &[u8], format: Option<u8>) -> bool {
+        data_format_versions.is_empty()
+            || format
+                .map(|format| data_format_versions.contains(&format))
+                .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_allowlist_none_matches_every_address() {
+        let address: BDAddr = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        assert!(Filter::mac_matches(None, address));
+    }
+
+    #[test]
+    fn mac_allowlist_rejects_addresses_not_listed() {
+        let allowed: BDAddr = "AA:BB:CC:DD:EE:FF".parse().unwrap();
+        let other: BDAddr = "11:22:33:44:55:66".parse().unwrap();
+        assert!(Filter::mac_matches(Some(&[allowed]), allowed));
+        assert!(!Filter::mac_matches(Some(&[allowed]), other));
+    }
+
+    #[test]
+    fn empty_data_format_versions_matches_everything() {
+        assert!(Filter::format_matches(&[], None));
+        assert!(Filter::format_matches(&[], Some(5)));
+    }
+
+    #[test]
+    fn data_format_versions_rejects_unlisted_or_missing_format() {
+        assert!(Filter::format_matches(&[3, 5], Some(5)));
+        assert!(!Filter::format_matches(&[3, 5], Some(4)));
+        assert!(!Filter::format_matches(&[3, 5], None));
+    }
+}
+
+/// One registered handler and the `Filter` that gates which measurements reach it.
+struct Subscriber {
+    filter: Filter,
+    handler: Box<dyn Fn(Result<Measurement, ParseError>) + Send + Sync>,
+}
+
+/// Fan-out registry of measurement subscribers, replacing the single `Box<dyn Fn>` callback that
+/// `on_measurement` used to invoke for every event. Each subscriber gets its own `Filter`, so the
+/// stdout printer, InfluxDB batcher, and Prometheus updater can be scoped independently (e.g. to
+/// a MAC allow-list or a subset of data format versions) instead of one closure handling all of
+/// them under one global filter.
+#[derive(Default)]
+pub struct MeasurementStream {
+    subscribers: Vec<Subscriber>,
+}
+
+impl MeasurementStream {
+    pub fn new() -> Self {
+        MeasurementStream::default()
+    }
+
+    /// Registers `handler` to receive every measurement matching `filter`. Parse errors bypass
+    /// filtering and are delivered to every subscriber, matching the previous behavior of
+    /// reporting them unconditionally regardless of format/MAC.
+    pub fn subscribe(
+        mut self,
+        filter: Filter,
+        handler: Box<dyn Fn(Result<Measurement, ParseError>) + Send + Sync>,
+    ) -> Self {
+        self.subscribers.push(Subscriber { filter, handler });
+        self
+    }
+
+    fn dispatch(&self, result: Result<Measurement, ParseError>) {
+        match result {
+            Ok(measurement) => {
+                for subscriber in &self.subscribers {
+                    if subscriber.filter.matches(&measurement) {
+                        (subscriber.handler)(Ok(measurement.clone()));
+                    }
+                }
+            }
+            Err(error) => {
+                for subscriber in &self.subscribers {
+                    (subscriber.handler)(Err(error.clone()));
+                }
+            }
         }
     }
+}
+
+// Stream of RuuviTag measurements that gets fanned out to every subscriber registered on
+// `stream`. In passive mode, frames are decoded straight from the manufacturer-data advertisement
+// event instead of triggering a peripheral()/properties() round-trip per event, which cuts
+// per-event latency and CPU when many tags are in range. The scan request issued to the adapter
+// is the same either way - btleplug doesn't expose a passive/active knob on `start_scan` - so
+// this does not reduce RuuviTag-side scan-response traffic.
+//
+// Runs as a supervised loop: an adapter error or event-stream termination (USB dongle reset,
+// Bluetooth service restart, ...) is logged and retried with capped exponential backoff instead
+// of tearing down the whole process, so this can run unattended as a long-lived service.
+pub async fn on_measurement(
+    adapter_selector: Option<String>,
+    passive: bool,
+    stream: MeasurementStream,
+) -> Result<(), btleplug::Error> {
+    let manager: btleplug::platform::Manager = btleplug::platform::Manager::new().await?;
+    let stream = Arc::new(stream);
+
+    let mut adapter = wait_for_adapter(&manager, adapter_selector.as_deref()).await;
+    let mut backoff = Duration::from_secs(1);
 
-    Err(btleplug::Error::NotSupported(String::from("No events received")))
+    loop {
+        let delivered_any = match run_scan(&adapter, passive, &stream).await {
+            Ok(delivered_any) => {
+                eprintln!("warning: bluetooth event stream ended, reconnecting");
+                delivered_any
+            }
+            Err(error) => {
+                eprintln!("warning: scan failed ({}), reconnecting", error);
+                false
+            }
+        };
+
+        if delivered_any {
+            backoff = Duration::from_secs(1);
+        }
+        eprintln!("retrying in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+
+        adapter = wait_for_adapter(&manager, adapter_selector.as_deref()).await;
+    }
 }